@@ -3,9 +3,11 @@
 
 use serde::{Deserialize, Serialize};
 use std::env;
-use std::sync::{LazyLock, RwLock};
-use std::time::Instant;
-use tiny_http::{Header, Method, Response, Server};
+use std::sync::mpsc;
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server};
 
 // ============================================================================
 // Constantes
@@ -95,12 +97,41 @@ fn cache_valide() -> bool {
         .is_some_and(|t| t.elapsed().as_secs() < CACHE_TTL_SECS)
 }
 
+static RAFRAICHISSEMENT_EN_COURS: LazyLock<std::sync::atomic::AtomicBool> =
+    LazyLock::new(|| std::sync::atomic::AtomicBool::new(false));
+
+/// Rafraîchit le cache si nécessaire.
+///
+/// Invariant : même avec plusieurs workers concurrents, le cache n'est
+/// rafraîchi qu'une seule fois par `CACHE_TTL_SECS`. Un thread qui trouve le
+/// cache expiré pendant qu'un autre le rafraîchit déjà renonce simplement et
+/// sert l'ancien cache plutôt que de dupliquer l'appel à l'API TAN.
 fn assurer_cache_frais() {
-    if !cache_valide()
-        && let Err(e) = rafraichir_cache()
+    use std::sync::atomic::Ordering;
+
+    if cache_valide() {
+        return;
+    }
+
+    if RAFRAICHISSEMENT_EN_COURS
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
     {
+        return;
+    }
+
+    // Un autre thread a pu terminer son rafraîchissement entre le premier
+    // `cache_valide()` et la prise du drapeau : revérifier avant l'appel.
+    if cache_valide() {
+        RAFRAICHISSEMENT_EN_COURS.store(false, Ordering::SeqCst);
+        return;
+    }
+
+    if let Err(e) = rafraichir_cache() {
         eprintln!("[WARN] Échec rafraîchissement cache : {}", e);
     }
+
+    RAFRAICHISSEMENT_EN_COURS.store(false, Ordering::SeqCst);
 }
 
 #[inline]
@@ -163,6 +194,295 @@ impl ReponseLaMetric {
     }
 }
 
+// ============================================================================
+// CORS
+// ============================================================================
+
+/// Politique d'origine autorisée pour les réponses CORS.
+#[derive(Clone)]
+enum Origin {
+    /// Aucun en-tête CORS n'est envoyé (comportement historique).
+    None,
+    /// `Access-Control-Allow-Origin: *` pour toute origine.
+    Star,
+    /// Liste blanche d'origines exactes, reflétées si elles matchent.
+    List(Vec<String>),
+}
+
+#[derive(Clone)]
+struct CorsConfig {
+    origin: Origin,
+    allowed_headers: String,
+}
+
+const CORS_ALLOWED_METHODS: &str = "GET, OPTIONS";
+const CORS_MAX_AGE_SECS: &str = "86400";
+
+impl CorsConfig {
+    fn from_env() -> Self {
+        let origin = match env::var("CORS_ALLOWED_ORIGINS") {
+            Ok(v) if v.trim() == "*" => Origin::Star,
+            Ok(v) if !v.trim().is_empty() => {
+                Origin::List(v.split(',').map(|o| o.trim().to_string()).collect())
+            }
+            _ => Origin::None,
+        };
+
+        CorsConfig {
+            origin,
+            allowed_headers: env::var("CORS_ALLOWED_HEADERS")
+                .unwrap_or_else(|_| "Content-Type, Authorization".into()),
+        }
+    }
+
+    /// Origine à renvoyer dans `Access-Control-Allow-Origin`, le cas échéant.
+    fn allow_origin(&self, request_origin: Option<&str>) -> Option<String> {
+        match &self.origin {
+            Origin::None => None,
+            Origin::Star => Some("*".to_string()),
+            Origin::List(liste) => {
+                let demandee = request_origin?;
+                liste
+                    .iter()
+                    .any(|o| o == demandee)
+                    .then(|| demandee.to_string())
+            }
+        }
+    }
+}
+
+static CORS_CONFIG: LazyLock<CorsConfig> = LazyLock::new(CorsConfig::from_env);
+
+fn header(name: &str, value: &str) -> Option<Header> {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).ok()
+}
+
+/// En-têtes CORS à ajouter à toute réponse (préflight ou normale).
+fn cors_headers(request_origin: Option<&str>) -> Vec<Header> {
+    CORS_CONFIG
+        .allow_origin(request_origin)
+        .into_iter()
+        .filter_map(|origin| header("Access-Control-Allow-Origin", &origin))
+        .collect()
+}
+
+fn cors_preflight_headers(request_origin: Option<&str>) -> Vec<Header> {
+    let mut headers = cors_headers(request_origin);
+    if headers.is_empty() {
+        return headers;
+    }
+    headers.extend(
+        [
+            header("Access-Control-Allow-Methods", CORS_ALLOWED_METHODS),
+            header("Access-Control-Allow-Headers", &CORS_CONFIG.allowed_headers),
+            header("Access-Control-Max-Age", CORS_MAX_AGE_SECS),
+        ]
+        .into_iter()
+        .flatten(),
+    );
+    headers
+}
+
+fn origine_requete(request: &tiny_http::Request) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Origin"))
+        .map(|h| h.value.as_str().to_string())
+}
+
+// ============================================================================
+// Authentification par clé API
+// ============================================================================
+
+/// État de l'authentification pour une requête donnée.
+enum AuthStatus {
+    Authenticated,
+    /// Identifiant manquant (401) ou invalide (403).
+    Unauthenticated {
+        status: u16,
+    },
+    /// Aucun `AUTH_TOKEN` configuré : la couche est transparente.
+    Disabled,
+}
+
+/// Emplacement où lire le jeton dans la requête.
+enum AuthSource {
+    /// En-tête `Authorization: Bearer <token>`.
+    Header,
+    /// Cookie `auth_token=<token>`.
+    Cookie,
+    /// Paramètre de requête `token=<token>`.
+    Query,
+}
+
+struct AuthConfig {
+    token: Option<String>,
+    source: AuthSource,
+}
+
+impl AuthConfig {
+    fn from_env() -> Self {
+        let token = env::var("AUTH_TOKEN").ok().filter(|t| !t.is_empty());
+        let source = match env::var("AUTH_SOURCE").as_deref() {
+            Ok("cookie") => AuthSource::Cookie,
+            Ok("query") => AuthSource::Query,
+            _ => AuthSource::Header,
+        };
+
+        AuthConfig { token, source }
+    }
+}
+
+static AUTH_CONFIG: LazyLock<AuthConfig> = LazyLock::new(AuthConfig::from_env);
+
+/// Comparaison en temps constant pour éviter les attaques par timing.
+fn eq_temps_constant(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes()
+        .zip(b.bytes())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+fn credential_query(query: &str) -> Option<String> {
+    query.split('&').find_map(|part| {
+        let (cle, valeur) = part.split_once('=')?;
+        if cle != "token" {
+            return None;
+        }
+        Some(urlencoding::decode(valeur).ok()?.to_string())
+    })
+}
+
+fn credential_requete(request: &tiny_http::Request, query: &str) -> Option<String> {
+    match AUTH_CONFIG.source {
+        AuthSource::Header => request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+            .map(|s| s.to_string()),
+        AuthSource::Cookie => request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Cookie"))
+            .and_then(|h| {
+                h.value.as_str().split(';').find_map(|kv| {
+                    let (cle, valeur) = kv.trim().split_once('=')?;
+                    (cle == "auth_token").then(|| valeur.to_string())
+                })
+            }),
+        AuthSource::Query => credential_query(query),
+    }
+}
+
+/// Comme `credential_requete`, mais retombe toujours sur `?token=` en plus
+/// de la source configurée.
+///
+/// La page HTML (`/ui`, `/`) est chargée par une simple navigation de
+/// navigateur, qui ne peut pas poser d'en-tête ou de cookie personnalisé :
+/// sans ce secours, activer `AUTH_TOKEN` avec la source par défaut
+/// (`AuthSource::Header`) rendrait la page de configuration définitivement
+/// inaccessible.
+fn credential_ui(request: &tiny_http::Request, query: &str) -> Option<String> {
+    credential_requete(request, query).or_else(|| credential_query(query))
+}
+
+/// Vérifie l'authentification avant de dispatcher vers un handler protégé.
+///
+/// Aux appelants de mapper `Unauthenticated { status }` sur une réponse
+/// `ReponseLaMetric::erreur("Auth")` avec ce code de statut.
+fn verifier_auth(request: &tiny_http::Request, query: &str) -> AuthStatus {
+    verifier_auth_avec(credential_requete(request, query))
+}
+
+/// Variante de `verifier_auth` pour la page HTML : voir `credential_ui`.
+fn verifier_auth_ui(request: &tiny_http::Request, query: &str) -> AuthStatus {
+    verifier_auth_avec(credential_ui(request, query))
+}
+
+fn verifier_auth_avec(credential: Option<String>) -> AuthStatus {
+    let Some(attendu) = &AUTH_CONFIG.token else {
+        return AuthStatus::Disabled;
+    };
+
+    match credential {
+        None => AuthStatus::Unauthenticated { status: 401 },
+        Some(fourni) if eq_temps_constant(&fourni, attendu) => AuthStatus::Authenticated,
+        Some(_) => AuthStatus::Unauthenticated { status: 403 },
+    }
+}
+
+// ============================================================================
+// Compression des réponses
+// ============================================================================
+
+/// En dessous de cette taille, compresser coûte plus cher que ça ne rapporte.
+const COMPRESSION_SEUIL_OCTETS: usize = 256;
+
+enum Encodage {
+    Brotli,
+    Gzip,
+    Identite,
+}
+
+#[inline]
+fn compression_activee() -> bool {
+    env::var("DISABLE_COMPRESSION").is_err()
+}
+
+fn encodage_prefere(accept_encoding: &str) -> Encodage {
+    let accept_encoding = accept_encoding.to_lowercase();
+    if accept_encoding.contains("br") {
+        Encodage::Brotli
+    } else if accept_encoding.contains("gzip") {
+        Encodage::Gzip
+    } else {
+        Encodage::Identite
+    }
+}
+
+fn compresser_brotli(data: &[u8]) -> Vec<u8> {
+    let mut sortie = Vec::new();
+    let params = brotli::enc::BrotliEncoderParams::default();
+    let _ = brotli::BrotliCompress(&mut std::io::Cursor::new(data), &mut sortie, &params);
+    sortie
+}
+
+fn compresser_gzip(data: &[u8]) -> Vec<u8> {
+    use flate2::{Compression, write::GzEncoder};
+    use std::io::Write;
+
+    let mut encodeur = GzEncoder::new(Vec::new(), Compression::default());
+    let _ = encodeur.write_all(data);
+    encodeur.finish().unwrap_or_default()
+}
+
+/// Compresse le corps d'une réponse selon l'`Accept-Encoding` du client.
+///
+/// Retourne les octets à envoyer (compressés ou non) ainsi que l'en-tête
+/// `Content-Encoding` correspondant, absent en cas de repli sur l'identité.
+fn compresser_reponse(body: &str, accept_encoding: Option<&str>) -> (Vec<u8>, Option<Header>) {
+    let octets = body.as_bytes();
+
+    if !compression_activee() || octets.len() < COMPRESSION_SEUIL_OCTETS {
+        return (octets.to_vec(), None);
+    }
+
+    let Some(accept_encoding) = accept_encoding else {
+        return (octets.to_vec(), None);
+    };
+
+    match encodage_prefere(accept_encoding) {
+        Encodage::Brotli => (compresser_brotli(octets), header("Content-Encoding", "br")),
+        Encodage::Gzip => (compresser_gzip(octets), header("Content-Encoding", "gzip")),
+        Encodage::Identite => (octets.to_vec(), None),
+    }
+}
+
 // ============================================================================
 // Parsing URL simple (sans dépendance)
 // ============================================================================
@@ -350,6 +670,254 @@ fn handle_info() -> String {
     )
 }
 
+// ============================================================================
+// Page de configuration HTML
+// ============================================================================
+
+const UI_TEMPLATE: &str = include_str!("ui.html");
+
+// Header Content-Type HTML pré-alloué
+static HTML_HEADER: LazyLock<Header> = LazyLock::new(|| {
+    Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap()
+});
+
+/// Injecte les arrêts populaires, la version du crate et le jeton d'auth
+/// (le cas échéant) dans le template.
+///
+/// La page n'est servie qu'à qui a déjà passé `verifier_auth_ui`, donc elle
+/// connaît déjà le secret ; le JS le rattache en `token=` à ses appels à
+/// `/stops` et à l'URL de `/` qu'il génère. Le jeton est encodé en littéral JSON (et non
+/// substitué tel quel) : rien ne garantit qu'il ne contient pas de guillemet
+/// ou de `</script>`, ce qui casserait sinon la page.
+fn rendu_ui() -> String {
+    // `serde_json` n'échappe pas les `/` : le faire à la main pour qu'un
+    // jeton contenant `</script>` ne puisse pas clore la balise en avance.
+    let jeton_js = serde_json::to_string(AUTH_CONFIG.token.as_deref().unwrap_or(""))
+        .unwrap_or_else(|_| "\"\"".to_string())
+        .replace("</", "<\\/");
+
+    UI_TEMPLATE
+        .replace("{{POPULAR_STOPS}}", ARRETS_POPULAIRES)
+        .replace("{{VERSION}}", env!("CARGO_PKG_VERSION"))
+        .replace("{{AUTH_TOKEN}}", &jeton_js)
+}
+
+/// La requête préfère-t-elle du HTML à du JSON, d'après son `Accept` ?
+///
+/// On ne regarde que l'entrée la plus prioritaire : un navigateur qui
+/// navigue vers `/` l'envoie en tête (`text/html,...`), alors qu'un client
+/// API envoie `application/json` ou `*/*`.
+fn accept_prefere_html(accept: &str) -> bool {
+    accept
+        .split(',')
+        .next()
+        .is_some_and(|premier| premier.trim().starts_with("text/html"))
+}
+
+// ============================================================================
+// Pool de workers
+// ============================================================================
+
+const DEFAULT_WORKERS: usize = 4;
+/// Intervalle entre deux passages du sweeper sur les jobs en cours.
+const SWEEP_INTERVAL_MS: u64 = 250;
+
+/// Un job HTTP en cours de traitement par un worker.
+///
+/// La requête est déposée dans un `Mutex` partagé avec le sweeper : le
+/// premier des deux (worker ou sweeper) à la `take()` est celui qui répond,
+/// l'autre la trouve déjà vide et n'a rien à faire.
+struct JobEnCours {
+    request: Mutex<Option<Request>>,
+    echeance: Instant,
+}
+
+fn nombre_workers() -> usize {
+    env::var("WORKERS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_WORKERS)
+}
+
+/// Traite une requête complète : CORS, auth, dispatch, compression, réponse.
+fn traiter_requete(request: Request) {
+    let origine = origine_requete(&request);
+
+    if *request.method() == Method::Options {
+        let mut response = Response::from_string("").with_status_code(204);
+        for h in cors_preflight_headers(origine.as_deref()) {
+            response = response.with_header(h);
+        }
+        let _ = request.respond(response);
+        return;
+    }
+
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+
+    let accept = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Accept"))
+        .map(|h| h.value.as_str().to_string());
+    let veut_html = accept.as_deref().is_some_and(accept_prefere_html);
+
+    let accept_encoding = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Accept-Encoding"))
+        .map(|h| h.value.as_str().to_string());
+
+    if *request.method() == Method::Get && (path == "/ui" || (path == "/" && veut_html)) {
+        let (status, corps_brut, est_html) = match verifier_auth_ui(&request, query) {
+            AuthStatus::Unauthenticated { status } => {
+                (status, ReponseLaMetric::erreur("Auth"), false)
+            }
+            _ => (200, rendu_ui(), true),
+        };
+
+        let (corps, encodage_header) = compresser_reponse(&corps_brut, accept_encoding.as_deref());
+
+        let mut response = Response::from_data(corps)
+            .with_status_code(status)
+            .with_header(if est_html {
+                HTML_HEADER.clone()
+            } else {
+                JSON_HEADER.clone()
+            });
+
+        if let Some(h) = encodage_header {
+            response = response.with_header(h);
+        }
+
+        for h in cors_headers(origine.as_deref()) {
+            response = response.with_header(h);
+        }
+
+        let _ = request.respond(response);
+        return;
+    }
+
+    let (status, body) = if *request.method() != Method::Get {
+        (405, r#"{"error":"Method not allowed"}"#.to_string())
+    } else {
+        match path {
+            "/" => match verifier_auth(&request, query) {
+                AuthStatus::Unauthenticated { status } => (status, ReponseLaMetric::erreur("Auth")),
+                _ => {
+                    let params = parse_query(query);
+                    handle_principal(&params)
+                }
+            },
+            "/health" => (200, "OK".to_string()),
+            "/stops" => match verifier_auth(&request, query) {
+                AuthStatus::Unauthenticated { status } => (status, ReponseLaMetric::erreur("Auth")),
+                _ => {
+                    let params = parse_query(query);
+                    handle_stops(&params)
+                }
+            },
+            "/popular-stops" => (200, ARRETS_POPULAIRES.to_string()),
+            "/info" => (200, handle_info()),
+            _ => (404, r#"{"error":"Not found"}"#.to_string()),
+        }
+    };
+
+    let (corps, encodage_header) = compresser_reponse(&body, accept_encoding.as_deref());
+
+    let mut response = Response::from_data(corps)
+        .with_status_code(status)
+        .with_header(JSON_HEADER.clone());
+
+    if let Some(h) = encodage_header {
+        response = response.with_header(h);
+    }
+
+    for h in cors_headers(origine.as_deref()) {
+        response = response.with_header(h);
+    }
+
+    let _ = request.respond(response);
+}
+
+/// Message d'erreur extrait d'un panic, pour le journaliser.
+fn message_panic(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("raison inconnue")
+}
+
+/// Boucle d'un worker : dépile un job, l'exécute s'il n'a pas déjà été
+/// récupéré par le sweeper, puis passe au suivant.
+///
+/// `traiter_requete` est protégée par `catch_unwind` : un panic dans un
+/// handler (p. ex. un slice sur un index hors frontière UTF-8) ne doit pas
+/// tuer ce thread pour de bon, sous peine de voir le pool rétrécir
+/// silencieusement job après job.
+fn boucle_worker(receveur: Arc<std::sync::Mutex<mpsc::Receiver<Arc<JobEnCours>>>>) {
+    loop {
+        let job = {
+            let Ok(receveur) = receveur.lock() else {
+                return;
+            };
+            receveur.recv()
+        };
+
+        let Ok(job) = job else {
+            return;
+        };
+
+        let Some(request) = job.request.lock().ok().and_then(|mut r| r.take()) else {
+            continue;
+        };
+
+        if let Err(payload) =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| traiter_requete(request)))
+        {
+            eprintln!(
+                "[ERROR] Un worker a paniqué en traitant une requête : {}",
+                message_panic(&*payload)
+            );
+        }
+    }
+}
+
+/// Boucle du sweeper : répond 504 aux jobs qui ont dépassé leur échéance
+/// avant qu'un worker n'ait eu l'occasion de les traiter.
+fn boucle_sweeper(jobs: Arc<Mutex<Vec<Arc<JobEnCours>>>>) {
+    loop {
+        thread::sleep(Duration::from_millis(SWEEP_INTERVAL_MS));
+
+        let Ok(mut jobs) = jobs.lock() else {
+            return;
+        };
+
+        let maintenant = Instant::now();
+        jobs.retain(|job| {
+            if job.request.lock().ok().is_none_or(|r| r.is_none()) {
+                // Déjà traité par un worker.
+                return false;
+            }
+
+            if maintenant < job.echeance {
+                return true;
+            }
+
+            if let Some(request) = job.request.lock().ok().and_then(|mut r| r.take()) {
+                let reponse = Response::from_string(ReponseLaMetric::erreur("Timeout"))
+                    .with_status_code(504)
+                    .with_header(JSON_HEADER.clone());
+                let _ = request.respond(reponse);
+            }
+
+            false
+        });
+    }
+}
+
 // ============================================================================
 // Point d'entrée
 // ============================================================================
@@ -380,33 +948,34 @@ fn main() {
     let server = Server::http(&addr).expect("Impossible de démarrer le serveur");
     eprintln!("[INFO] Serveur démarré sur {addr}");
 
+    let (emetteur, receveur) = mpsc::channel::<Arc<JobEnCours>>();
+    let receveur = Arc::new(Mutex::new(receveur));
+    let jobs_en_cours: Arc<Mutex<Vec<Arc<JobEnCours>>>> = Arc::new(Mutex::new(Vec::new()));
+
+    let nb_workers = nombre_workers();
+    eprintln!("[INFO] Démarrage de {nb_workers} workers");
+    for _ in 0..nb_workers {
+        let receveur = Arc::clone(&receveur);
+        thread::spawn(move || boucle_worker(receveur));
+    }
+
+    {
+        let jobs_en_cours = Arc::clone(&jobs_en_cours);
+        thread::spawn(move || boucle_sweeper(jobs_en_cours));
+    }
+
     for request in server.incoming_requests() {
-        let url = request.url().to_string();
-        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
-
-        let (status, body) = if *request.method() != Method::Get {
-            (405, r#"{"error":"Method not allowed"}"#.to_string())
-        } else {
-            match path {
-                "/" => {
-                    let params = parse_query(query);
-                    handle_principal(&params)
-                }
-                "/health" => (200, "OK".to_string()),
-                "/stops" => {
-                    let params = parse_query(query);
-                    handle_stops(&params)
-                }
-                "/popular-stops" => (200, ARRETS_POPULAIRES.to_string()),
-                "/info" => (200, handle_info()),
-                _ => (404, r#"{"error":"Not found"}"#.to_string()),
-            }
-        };
+        let job = Arc::new(JobEnCours {
+            request: Mutex::new(Some(request)),
+            echeance: Instant::now() + Duration::from_secs(HTTP_TIMEOUT_SECS),
+        });
 
-        let response = Response::from_string(&body)
-            .with_status_code(status)
-            .with_header(JSON_HEADER.clone());
+        if let Ok(mut jobs) = jobs_en_cours.lock() {
+            jobs.push(Arc::clone(&job));
+        }
 
-        let _ = request.respond(response);
+        if emetteur.send(job).is_err() {
+            eprintln!("[ERROR] Aucun worker disponible pour traiter la requête");
+        }
     }
 }